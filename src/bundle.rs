@@ -0,0 +1,93 @@
+//! Incremental git bundles for mirroring registry indices.
+//!
+//! Re-fetching (and re-uploading) a whole registry index every time it goes
+//! stale is wasteful once the index is hundreds of megabytes. Instead, once
+//! we've mirrored a base copy, every subsequent update ships a *thin*
+//! bundle: a small header listing the prerequisite ("have") commit id,
+//! followed by a packfile containing only the objects reachable from the
+//! new tip but not already reachable from that prerequisite. Clients
+//! reconstruct the current index by applying the chain of bundles on top of
+//! the base.
+
+use anyhow::{Context, Error};
+use std::io::Write as _;
+
+/// A single thin bundle: the new tip it was built for, plus its wire
+/// payload (header + packfile).
+pub struct Bundle {
+    pub tip: String,
+    pub data: bytes::Bytes,
+}
+
+/// Builds a thin bundle for `repo` containing everything reachable from
+/// `tip` that isn't already reachable from `have` (the last mirrored oid).
+pub fn create(repo: &gix::Repository, have: Option<&str>, tip: &str) -> Result<Bundle, Error> {
+    let tip_id = repo
+        .rev_parse_single(tip)
+        .with_context(|| format!("tip '{}' does not resolve to an object", tip))?
+        .detach();
+
+    let mut header = Vec::new();
+    writeln!(&mut header, "# v2 git bundle")?;
+    if let Some(have) = have {
+        let have_id = repo
+            .rev_parse_single(have)
+            .with_context(|| format!("have '{}' does not resolve to an object", have))?
+            .detach();
+        writeln!(&mut header, "-{}", have_id)?;
+    }
+    writeln!(&mut header, "{} HEAD", tip_id)?;
+    writeln!(&mut header)?;
+
+    // Only the objects new since `have` go in the packfile — this is what
+    // makes the bundle thin instead of a full re-pack of the repo.
+    let pack = crate::git::pack_objects_between(repo, have, tip)?;
+
+    let mut data = header;
+    data.extend_from_slice(&pack);
+
+    Ok(Bundle {
+        tip: tip_id.to_string(),
+        data: bytes::Bytes::from(data),
+    })
+}
+
+/// Reconstructs the current index at `dir` by applying a chain of
+/// incremental bundles, in order, on top of an already-cloned base.
+pub fn unbundle(dir: &std::path::Path, bundles: &[bytes::Bytes]) -> Result<(), Error> {
+    let repo =
+        gix::open(dir).with_context(|| format!("failed to open base repo at {}", dir.display()))?;
+
+    for bundle in bundles {
+        apply(&repo, bundle)?;
+    }
+
+    Ok(())
+}
+
+fn apply(repo: &gix::Repository, bundle: &bytes::Bytes) -> Result<(), Error> {
+    let pack = pack_payload(bundle)?;
+    repo.objects
+        .write_pack_from_data(pack)
+        .context("failed to unpack bundle contents")?;
+
+    Ok(())
+}
+
+/// Returns the packfile portion of `data`, stripping the bundle header (the
+/// `# v2 git bundle` line and prerequisite commit ids) if present. A raw
+/// full pack, eg. from [`crate::git::pack_objects`], has no such header and
+/// is returned unchanged, so this is safe to call on either.
+pub(crate) fn pack_payload(data: &[u8]) -> Result<&[u8], Error> {
+    if !data.starts_with(b"# v2 git bundle") {
+        return Ok(data);
+    }
+
+    let header_end = data
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map(|pos| pos + 2)
+        .context("malformed bundle: missing header terminator")?;
+
+    Ok(&data[header_end..])
+}
@@ -0,0 +1,326 @@
+//! The mirror's catalog of uploaded artifacts.
+//!
+//! `locked_crates` and `registry_index` used to learn what had already been
+//! mirrored by listing the entire backend bucket on every invocation, which
+//! is slow and doesn't survive interruption well. This module keeps a small,
+//! durable catalog (SQLite by default, or Postgres via `DATABASE_URL`) with
+//! one row per uploaded artifact, so those checks become indexed lookups.
+
+use crate::{Backend, Krate, Source};
+use anyhow::{Context, Error};
+use sqlx::any::{install_default_drivers, AnyPoolOptions};
+
+/// Connection pool over whichever backend `DATABASE_URL` points at
+pub type Db = sqlx::Pool<sqlx::Any>;
+
+/// Used when `DATABASE_URL` isn't set, so the mirror works without any
+/// external database being stood up first
+const DEFAULT_SQLITE_URL: &str = "sqlite://cacher.db?mode=rwc";
+
+static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("migrations");
+
+/// Opens the catalog database, creating and migrating a local SQLite file if
+/// `DATABASE_URL` isn't set in the environment.
+pub async fn open() -> Result<Db, Error> {
+    let url = std::env::var("DATABASE_URL").unwrap_or_else(|_| DEFAULT_SQLITE_URL.to_owned());
+
+    install_default_drivers();
+
+    let db = AnyPoolOptions::new()
+        .max_connections(10)
+        .connect(&url)
+        .await
+        .with_context(|| format!("failed to connect to catalog database at '{}'", url))?;
+
+    MIGRATOR
+        .run(&db)
+        .await
+        .context("failed to run catalog migrations")?;
+
+    Ok(db)
+}
+
+/// Records that `krate` has been uploaded to the backend, so future runs
+/// don't try to re-fetch and re-upload it.
+pub async fn record_upload(db: &Db, krate: &Krate) -> Result<(), Error> {
+    let cloud_id = krate.cloud_id().to_string();
+    let (source_kind, checksum, git_rev): (&str, &str, Option<&str>) = match &krate.source {
+        Source::Registry { registry, chksum } => (&registry.ident, chksum.as_str(), None),
+        Source::Git { rev, .. } => ("git", "", Some(rev.as_str())),
+    };
+
+    sqlx::query(
+        "INSERT INTO artifacts (cloud_id, name, version, source_kind, checksum, git_rev, uploaded_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)
+         ON CONFLICT (cloud_id) DO UPDATE SET uploaded_at = excluded.uploaded_at",
+    )
+    .bind(cloud_id)
+    .bind(&krate.name)
+    .bind(&krate.version)
+    .bind(source_kind)
+    .bind(checksum)
+    .bind(git_rev)
+    .bind(chrono::Utc::now())
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns true if `krate` is already recorded as uploaded in the catalog.
+pub async fn is_uploaded(db: &Db, krate: &Krate) -> Result<bool, Error> {
+    let cloud_id = krate.cloud_id().to_string();
+
+    let row: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM artifacts WHERE cloud_id = $1")
+        .bind(cloud_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+/// Returns the most recent `uploaded_at` recorded for `name`, eg. the
+/// synthetic krate name used for a registry index.
+pub async fn last_uploaded(
+    db: &Db,
+    name: &str,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, Error> {
+    let row: Option<(chrono::DateTime<chrono::Utc>,)> = sqlx::query_as(
+        "SELECT uploaded_at FROM artifacts WHERE name = $1 ORDER BY uploaded_at DESC LIMIT 1",
+    )
+    .bind(name)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|(uploaded_at,)| uploaded_at))
+}
+
+/// Returns the last commit oid recorded as mirrored for the index named
+/// `name`, if any.
+pub async fn last_index_oid(db: &Db, name: &str) -> Result<Option<String>, Error> {
+    let row: Option<(String,)> =
+        sqlx::query_as("SELECT last_oid FROM index_state WHERE name = $1")
+            .bind(name)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(row.map(|(oid,)| oid))
+}
+
+/// Records `oid` as the last commit mirrored for the index named `name`.
+pub async fn record_index_oid(db: &Db, name: &str, oid: &str) -> Result<(), Error> {
+    sqlx::query(
+        "INSERT INTO index_state (name, last_oid) VALUES ($1, $2)
+         ON CONFLICT (name) DO UPDATE SET last_oid = excluded.last_oid",
+    )
+    .bind(name)
+    .bind(oid)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the `git_rev` of every upload recorded for `name`, oldest first,
+/// eg. the ordered chain of base + incremental bundles for a registry index.
+pub async fn index_revisions(db: &Db, name: &str) -> Result<Vec<String>, Error> {
+    let rows: Vec<(String,)> = sqlx::query_as(
+        "SELECT git_rev FROM artifacts WHERE name = $1 AND git_rev IS NOT NULL ORDER BY uploaded_at ASC",
+    )
+    .bind(name)
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(|(rev,)| rev).collect())
+}
+
+/// Records that `registry`'s index listed `name`@`version` at `seen_at`,
+/// along with whether it's currently yanked.
+pub async fn record_eligibility(
+    db: &Db,
+    registry: &str,
+    name: &str,
+    version: &str,
+    yanked: bool,
+    seen_at: chrono::DateTime<chrono::Utc>,
+) -> Result<(), Error> {
+    sqlx::query(
+        "INSERT INTO eligibility (registry, name, version, yanked, last_seen) VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (registry, name, version) DO UPDATE SET yanked = excluded.yanked, last_seen = excluded.last_seen",
+    )
+    .bind(registry)
+    .bind(name)
+    .bind(version)
+    .bind(yanked)
+    .bind(seen_at)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns true if the stored artifact identified by `cloud_id` is
+/// *confirmed* safe to prune: we know its registry/name/version, we have an
+/// eligibility record for it, and that record says it's either yanked or
+/// hasn't been seen in the index within `retention`. Anything we can't
+/// positively confirm (no catalog record, no eligibility entry yet) is kept
+/// rather than deleted — the eligibility cache is an allow-list for deletion,
+/// not a default.
+///
+/// `artifacts.source_kind` already holds the registry ident for
+/// registry-sourced krates (see [`record_upload`]), so it doubles as the
+/// join key into `eligibility`, keeping the two registry-namespaced the same
+/// way `CloudId` is. Git-sourced artifacts (`source_kind` of `"git"`) never
+/// have a matching eligibility row and so are never prune-eligible here.
+pub async fn is_prune_eligible(
+    db: &Db,
+    cloud_id: &str,
+    retention: chrono::Duration,
+) -> Result<bool, Error> {
+    let artifact: Option<(String, String, String)> =
+        sqlx::query_as("SELECT name, version, source_kind FROM artifacts WHERE cloud_id = $1")
+            .bind(cloud_id)
+            .fetch_optional(db)
+            .await?;
+
+    let (name, version, registry) = match artifact {
+        // Nothing un-attributed (eg. a `reconcile`d placeholder row) is ever
+        // prune-eligible: without a real name/version we have no way to
+        // confirm it's actually yanked or stale.
+        Some((name, version, registry)) if !name.is_empty() && !version.is_empty() => {
+            (name, version, registry)
+        }
+        _ => return Ok(false),
+    };
+
+    let row: Option<(bool, chrono::DateTime<chrono::Utc>)> = sqlx::query_as(
+        "SELECT yanked, last_seen FROM eligibility WHERE registry = $1 AND name = $2 AND version = $3",
+    )
+    .bind(&registry)
+    .bind(&name)
+    .bind(&version)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(match row {
+        // No eligibility data yet for this (registry, name, version) — the
+        // cache hasn't walked it, so we can't confirm anything. Keep it.
+        None => false,
+        Some((yanked, last_seen)) => yanked || chrono::Utc::now() - last_seen > retention,
+    })
+}
+
+/// Clears the eligibility cache, forcing the next `registry_index` walk to
+/// re-evaluate every crate's yanked status and last-seen time from scratch.
+pub async fn bust_eligibility_cache(db: &Db) -> Result<(), Error> {
+    sqlx::query("DELETE FROM eligibility").execute(db).await?;
+
+    Ok(())
+}
+
+/// Repopulates the catalog from the backend's own listing. Used to recover a
+/// cold/empty database without wiping and re-mirroring everything.
+///
+/// The backend's listing only gives us `cloud_id`s, not the original
+/// name/version/checksum, so reconciled rows are recorded with those fields
+/// left blank rather than guessed. `is_prune_eligible` treats an
+/// un-attributed row as never eligible for deletion, so reconciling a cold
+/// DB can't turn around and immediately prune the store it just recovered.
+pub async fn reconcile(db: &Db, backend: &(dyn Backend + Sync)) -> Result<(), Error> {
+    let cloud_ids = backend.list().await?;
+
+    for cloud_id in cloud_ids {
+        sqlx::query(
+            "INSERT INTO artifacts (cloud_id, name, version, source_kind, checksum, git_rev, uploaded_at)
+             VALUES ($1, '', '', '', '', NULL, $2)
+             ON CONFLICT (cloud_id) DO NOTHING",
+        )
+        .bind(cloud_id)
+        .bind(chrono::Utc::now())
+        .execute(db)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_db() -> Db {
+        install_default_drivers();
+        let db = AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        MIGRATOR.run(&db).await.unwrap();
+        db
+    }
+
+    async fn insert_artifact(db: &Db, cloud_id: &str, name: &str, version: &str, source_kind: &str) {
+        sqlx::query(
+            "INSERT INTO artifacts (cloud_id, name, version, source_kind, checksum, git_rev, uploaded_at)
+             VALUES ($1, $2, $3, $4, '', NULL, $5)",
+        )
+        .bind(cloud_id)
+        .bind(name)
+        .bind(version)
+        .bind(source_kind)
+        .bind(chrono::Utc::now())
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    /// Regression test: `is_prune_eligible` used to default to `true` (safe
+    /// to delete) whenever the catalog or eligibility cache had no data for
+    /// a cloud id, which combined with `reconcile`'s blank-name placeholder
+    /// rows to make a cold reconcile immediately prune-eligible.
+    #[tokio::test]
+    async fn defaults_to_keep_when_uncertain() {
+        let db = test_db().await;
+        let retention = chrono::Duration::days(1);
+
+        // No catalog record at all.
+        assert!(!is_prune_eligible(&db, "unknown", retention).await.unwrap());
+
+        // A `reconcile`d placeholder row (blank name/version).
+        insert_artifact(&db, "placeholder", "", "", "").await;
+        assert!(!is_prune_eligible(&db, "placeholder", retention).await.unwrap());
+
+        // A real artifact, but the eligibility cache hasn't walked it yet.
+        insert_artifact(&db, "foo-cloud-id", "foo", "1.0.0", "crates-io").await;
+        assert!(!is_prune_eligible(&db, "foo-cloud-id", retention).await.unwrap());
+
+        // Only once the cache positively confirms it's yanked does it
+        // become eligible.
+        record_eligibility(&db, "crates-io", "foo", "1.0.0", true, chrono::Utc::now())
+            .await
+            .unwrap();
+        assert!(is_prune_eligible(&db, "foo-cloud-id", retention).await.unwrap());
+    }
+
+    /// Regression test: the eligibility cache used to be keyed by
+    /// (name, version) alone, so two registries serving the same
+    /// name/version could clobber each other's yanked/last_seen state.
+    #[tokio::test]
+    async fn eligibility_is_namespaced_by_registry() {
+        let db = test_db().await;
+        let retention = chrono::Duration::days(1);
+
+        insert_artifact(&db, "a-foo", "foo", "1.0.0", "mirror-a").await;
+        insert_artifact(&db, "b-foo", "foo", "1.0.0", "mirror-b").await;
+
+        // mirror-a's index says foo 1.0.0 is yanked...
+        record_eligibility(&db, "mirror-a", "foo", "1.0.0", true, chrono::Utc::now())
+            .await
+            .unwrap();
+
+        // ...which must not make mirror-b's copy of the same name/version
+        // prune-eligible too.
+        assert!(is_prune_eligible(&db, "a-foo", retention).await.unwrap());
+        assert!(!is_prune_eligible(&db, "b-foo", retention).await.unwrap());
+    }
+}
@@ -0,0 +1,134 @@
+//! Eligibility cache for pruning: tracks which (registry, name, version)
+//! tuples are currently listed in a registry index, and whether they've been
+//! yanked, by walking the index's commit tree while it's being mirrored.
+//! `mirror::prune` reads this cache rather than re-walking the index on
+//! every GC pass.
+
+use anyhow::Error;
+use log::warn;
+
+struct IndexEntry {
+    name: String,
+    version: String,
+    yanked: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct RawEntry {
+    name: String,
+    vers: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// Walks every crate file in `repo`'s tree at `rev` (one newline-delimited
+/// JSON entry per version, cargo's usual index layout) and records each
+/// version's yanked flag and last-seen time in `registry`'s eligibility
+/// cache.
+///
+/// This reads directly out of the git object database via `crate::git`
+/// rather than the filesystem, since `repo` is a bare mirror clone with no
+/// working tree to walk: its directory only contains git's internal object
+/// store (`objects/`, `refs/`, packfiles, ...), not a checkout of the
+/// index's per-crate JSON files.
+pub async fn refresh(
+    db: &crate::db::Db,
+    registry: &str,
+    repo: &gix::Repository,
+    rev: &str,
+    seen_at: chrono::DateTime<chrono::Utc>,
+) -> Result<(), Error> {
+    for entry in walk(repo, rev)? {
+        crate::db::record_eligibility(
+            db,
+            registry,
+            &entry.name,
+            &entry.version,
+            entry.yanked,
+            seen_at,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Returns true if `path` is something other than a per-crate index entry:
+/// `config.json`, or any path component (not just the final segment)
+/// starting with a dot, eg. the `.github/workflows/*.yml` directory present
+/// at the top of the real `crates.io-index` repo. Checking only the final
+/// segment let those files slip through and fail to parse as a per-crate
+/// entry.
+fn is_non_entry_path(path: &str) -> bool {
+    path.split('/').any(|segment| segment.starts_with('.')) || path == "config.json"
+}
+
+fn walk(repo: &gix::Repository, rev: &str) -> Result<Vec<IndexEntry>, Error> {
+    let mut entries = Vec::new();
+
+    for (path, contents) in crate::git::read_text_files(repo, rev)? {
+        if is_non_entry_path(&path) {
+            continue;
+        }
+
+        parse_entries(&path, &contents, &mut entries);
+    }
+
+    Ok(entries)
+}
+
+/// Parses `contents` as newline-delimited index entries, appending each one
+/// to `entries`. A single malformed/unexpected line is logged and skipped
+/// rather than aborting — which would otherwise discard every entry already
+/// collected from other files in the same walk.
+fn parse_entries(path: &str, contents: &str, entries: &mut Vec<IndexEntry>) {
+    for line in contents.lines().filter(|l| !l.is_empty()) {
+        let raw: RawEntry = match serde_json::from_str(line) {
+            Ok(raw) => raw,
+            Err(e) => {
+                warn!("skipping unparseable index entry in {}: {}", path, e);
+                continue;
+            }
+        };
+
+        entries.push(IndexEntry {
+            name: raw.name,
+            version: raw.vers,
+            yanked: raw.yanked,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_config_json_and_dotfiles_at_any_depth() {
+        assert!(is_non_entry_path("config.json"));
+        assert!(is_non_entry_path(".gitattributes"));
+        // Regression: a dotfile nested in a subdirectory (eg. the real
+        // crates.io-index's `.github/workflows/*.yml`) used to only be
+        // checked against its own final path segment, which doesn't start
+        // with a dot, and so slipped through.
+        assert!(is_non_entry_path(".github/workflows/ci.yml"));
+        assert!(!is_non_entry_path("se/rd/serde"));
+    }
+
+    #[test]
+    fn one_bad_line_does_not_discard_the_rest_of_the_file() {
+        let mut entries = Vec::new();
+        let contents = concat!(
+            "{\"name\":\"foo\",\"vers\":\"1.0.0\",\"yanked\":false}\n",
+            "not json at all\n",
+            "{\"name\":\"foo\",\"vers\":\"1.0.1\",\"yanked\":true}\n",
+        );
+
+        parse_entries("fo/oo/foo", contents, &mut entries);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].version, "1.0.0");
+        assert_eq!(entries[1].version, "1.0.1");
+        assert!(entries[1].yanked);
+    }
+}
@@ -0,0 +1,31 @@
+//! Retrieval of individual crate tarballs. Registry indices are fetched via
+//! [`crate::git`].
+
+use crate::{Krate, Source};
+use anyhow::Error;
+use bytes::Bytes;
+
+/// Downloads a single crate's `.crate` tarball from the registry it's
+/// sourced from. Uses the registry's `fetch_index` rather than `index`,
+/// since `[source]` replacement means the two can differ — `index` is only
+/// what shows up in `Cargo.lock`.
+pub async fn from_registry(client: &reqwest::Client, krate: &Krate) -> Result<Bytes, Error> {
+    let index = match &krate.source {
+        Source::Registry { registry, .. } => &registry.fetch_index,
+        Source::Git { .. } => anyhow::bail!("{} is not a registry-sourced crate", krate),
+    };
+
+    let url = format!(
+        "{}/api/v1/crates/{}/{}/download",
+        index.as_str().trim_end_matches('/'),
+        krate.name,
+        krate.version
+    );
+
+    let res = client.get(&url).send().await?.error_for_status()?;
+    let bytes = res.bytes().await?;
+
+    crate::integrity::verify_checksum(krate, &bytes)?;
+
+    Ok(bytes)
+}
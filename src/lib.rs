@@ -1,7 +1,7 @@
 #![warn(clippy::all)]
 #![warn(rust_2018_idioms)]
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use std::{
     collections::BTreeMap,
     convert::TryFrom,
@@ -12,7 +12,12 @@ use std::{
 pub use url::Url;
 
 pub mod backends;
+pub mod bundle;
+pub mod db;
+pub mod eligibility;
 mod fetch;
+pub mod git;
+pub mod integrity;
 pub mod mirror;
 pub mod sync;
 pub mod util;
@@ -35,9 +40,44 @@ struct LockContents {
     metadata: BTreeMap<String, String>,
 }
 
+/// An alternative/private registry, as configured in `.cargo/config.toml`'s
+/// `[registries]` table. crates.io itself is represented as a `Registry` with
+/// the well-known `crates-io` ident, matching cargo's own special casing.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub struct Registry {
+    /// The index URL the registry is served from, and the one that appears
+    /// in `Cargo.lock`'s `registry+<url>` source strings. Source
+    /// replacement (`.cargo/config.toml`'s `[source]` table) doesn't change
+    /// what ends up in the lock file, so this stays the "nominal" url even
+    /// when `fetch_index` points somewhere else.
+    pub index: Url,
+    /// A short identifier for the registry, used to disambiguate storage
+    /// locations when multiple registries are mirrored
+    pub ident: String,
+    /// The index artifacts are actually downloaded from. Equal to `index`
+    /// unless `[source]` redirects this registry via `replace-with`.
+    pub fetch_index: Url,
+}
+
+impl Registry {
+    /// The well-known crates.io registry, matching cargo's own special-cased
+    /// `crates-io` identifier
+    pub fn crates_io() -> Self {
+        let index = Url::parse("https://github.com/rust-lang/crates.io-index").unwrap();
+        Self {
+            fetch_index: index.clone(),
+            index,
+            ident: "crates-io".to_owned(),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
 pub enum Source {
-    CratesIo(String),
+    Registry {
+        registry: Arc<Registry>,
+        chksum: String,
+    },
     Git {
         url: Url,
         rev: String,
@@ -76,10 +116,32 @@ impl Source {
         })
     }
 
+    /// Like [`Source::from_git_url`], but resolves the revision specifier
+    /// against `repo`'s actual object graph instead of just checking its
+    /// length, so a plausible-looking but nonexistent rev is rejected up
+    /// front rather than failing later when something tries to fetch it.
+    pub fn from_git_url_verified(url: &Url, repo: &gix::Repository) -> Result<Self, Error> {
+        let rev = match url.query_pairs().find(|(k, _)| k == "rev") {
+            Some((_, rev)) => rev,
+            None => anyhow::bail!("url doesn't contain a revision specifier"),
+        };
+
+        let full_rev = git::resolve_rev(repo, &rev)?;
+
+        let canonicalized = util::Canonicalized::try_from(url)?;
+        let ident = canonicalized.ident();
+
+        Ok(Source::Git {
+            url: canonicalized.into(),
+            ident,
+            rev: full_rev,
+        })
+    }
+
     pub(crate) fn is_git(&self) -> bool {
         match self {
-            Source::CratesIo(_) => false,
-            _ => true,
+            Source::Registry { .. } => false,
+            Source::Git { .. } => true,
         }
     }
 }
@@ -115,8 +177,8 @@ impl Krate {
 
 impl fmt::Display for Krate {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let typ = match &self.source {
-            Source::CratesIo(_) => "crates.io",
+        let typ: &str = match &self.source {
+            Source::Registry { registry, .. } => registry.ident.as_str(),
             Source::Git { .. } => "git",
         };
 
@@ -131,7 +193,9 @@ pub struct LocalId<'a> {
 impl<'a> fmt::Display for LocalId<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.inner.source {
-            Source::CratesIo(_) => write!(f, "{}-{}.crate", self.inner.name, self.inner.version),
+            Source::Registry { .. } => {
+                write!(f, "{}-{}.crate", self.inner.name, self.inner.version)
+            }
             Source::Git { ident, .. } => write!(f, "{}", &ident),
         }
     }
@@ -144,7 +208,9 @@ pub struct CloudId<'a> {
 impl<'a> fmt::Display for CloudId<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.inner.source {
-            Source::CratesIo(chksum) => write!(f, "{}", chksum),
+            // Namespace the checksum by registry so that two registries that
+            // happen to serve the same name/version pair can't collide
+            Source::Registry { registry, chksum } => write!(f, "{}-{}", registry.ident, chksum),
             Source::Git { ident, rev, .. } => write!(f, "{}-{}", ident, rev),
         }
     }
@@ -176,10 +242,13 @@ pub struct Ctx {
     pub backend: Storage,
     pub krates: Vec<Krate>,
     pub root_dir: PathBuf,
+    /// Catalog of what's already been uploaded, so we don't have to re-list
+    /// the backend on every run
+    pub db: db::Db,
 }
 
 impl Ctx {
-    pub fn new(
+    pub async fn new(
         root_dir: Option<PathBuf>,
         backend: Storage,
         krates: Vec<Krate>,
@@ -189,6 +258,7 @@ impl Ctx {
             backend,
             krates,
             root_dir: root_dir.unwrap_or_else(|| PathBuf::from(".")),
+            db: db::open().await?,
         })
     }
 
@@ -207,10 +277,124 @@ pub trait Backend {
     async fn upload(&self, source: bytes::Bytes, krate: &Krate) -> Result<usize, Error>;
     async fn list(&self) -> Result<Vec<String>, Error>;
     async fn updated(&self, krate: &Krate) -> Result<Option<chrono::DateTime<chrono::Utc>>, Error>;
+    /// Deletes the stored object identified by `cloud_id`, eg. during a
+    /// `mirror::prune` pass.
+    async fn delete(&self, cloud_id: &str) -> Result<(), Error>;
     fn set_prefix(&mut self, prefix: &str);
+
+    /// Repopulates a (possibly cold/empty) catalog database from this
+    /// backend's own listing, so the catalog can be recovered without
+    /// wiping and re-mirroring everything.
+    async fn reconcile(&self, db: &db::Db) -> Result<(), Error>
+    where
+        Self: Sync + Sized,
+    {
+        db::reconcile(db, self).await
+    }
 }
 
-pub fn read_lock_file<P: AsRef<Path>>(lock_path: P) -> Result<Vec<Krate>, Error> {
+/// Reads the `[registries]` and `[source]` tables out of a
+/// `.cargo/config.toml`, returning the set of registries that lock file
+/// sources may be resolved against. crates.io is always included, even if
+/// the config file doesn't exist or doesn't mention it, since it's cargo's
+/// implicit default registry.
+///
+/// `[source.<name>]` entries with a `replace-with` are resolved onto the
+/// matching registry's `fetch_index`, since that's cargo's standard way of
+/// pointing a shop's builds at an internal mirror while leaving
+/// `Cargo.lock`'s recorded source (`index`) untouched.
+pub fn read_cargo_config<P: AsRef<Path>>(config_path: P) -> Result<Vec<Arc<Registry>>, Error> {
+    use tracing::error;
+
+    #[derive(serde::Deserialize)]
+    struct RegistryConfig {
+        index: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct SourceConfig {
+        #[serde(rename = "replace-with", default)]
+        replace_with: Option<String>,
+        #[serde(default)]
+        registry: Option<String>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct CargoConfig {
+        #[serde(default)]
+        registries: BTreeMap<String, RegistryConfig>,
+        #[serde(default)]
+        source: BTreeMap<String, SourceConfig>,
+    }
+
+    let mut registries = vec![Registry::crates_io()];
+
+    let config_path = config_path.as_ref();
+    if !config_path.exists() {
+        return Ok(registries.into_iter().map(Arc::new).collect());
+    }
+
+    let contents = std::fs::read_to_string(config_path)
+        .with_context(|| format!("failed to read {}", config_path.display()))?;
+    let config: CargoConfig = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", config_path.display()))?;
+
+    for (ident, reg) in &config.registries {
+        let index = Url::parse(&reg.index)
+            .with_context(|| format!("invalid index url for registry '{}'", ident))?;
+        registries.push(Registry {
+            fetch_index: index.clone(),
+            index,
+            ident: ident.clone(),
+        });
+    }
+
+    for (name, src) in &config.source {
+        let Some(replacement_name) = &src.replace_with else {
+            continue;
+        };
+
+        let Some(replacement) = config.source.get(replacement_name) else {
+            error!(
+                "source '{}' replaces with unknown source '{}'",
+                name, replacement_name
+            );
+            continue;
+        };
+
+        let Some(replacement_registry) = &replacement.registry else {
+            error!(
+                "replacement source '{}' for '{}' has no 'registry' url",
+                replacement_name, name
+            );
+            continue;
+        };
+
+        let Some(registry) = registries.iter_mut().find(|r| &r.ident == name) else {
+            continue;
+        };
+
+        registry.fetch_index = Url::parse(replacement_registry).with_context(|| {
+            format!(
+                "invalid replacement registry url for source '{}'",
+                replacement_name
+            )
+        })?;
+    }
+
+    Ok(registries.into_iter().map(Arc::new).collect())
+}
+
+/// `git_cache_dir` is where git sources referenced by the lock file get
+/// cloned/fetched to so their revs can be validated against a real object
+/// graph (see [`Source::from_git_url_verified`]), rather than just checking
+/// the rev specifier's length. One clone per distinct repository is reused
+/// across all of its package entries in the lock file.
+pub fn read_lock_file<P: AsRef<Path>>(
+    lock_path: P,
+    registries: &[Arc<Registry>],
+    git_cache_dir: &Path,
+) -> Result<Vec<Krate>, Error> {
     use std::fmt::Write;
     use tracing::{debug, error};
 
@@ -221,6 +405,7 @@ pub fn read_lock_file<P: AsRef<Path>>(lock_path: P) -> Result<Vec<Krate>, Error>
 
     let mut lookup = String::with_capacity(128);
     let mut krates = Vec::with_capacity(locks.package.len());
+    let mut git_repos: BTreeMap<String, gix::Repository> = BTreeMap::new();
 
     for p in locks.package {
         let source = match p.source.as_ref() {
@@ -231,32 +416,42 @@ pub fn read_lock_file<P: AsRef<Path>>(lock_path: P) -> Result<Vec<Krate>, Error>
             }
         };
 
-        if source == "registry+https://github.com/rust-lang/crates.io-index" {
+        let registry = source
+            .strip_prefix("registry+")
+            .and_then(|url| registries.iter().find(|reg| reg.index.as_str() == url));
+
+        if let Some(registry) = registry {
             match p.checksum {
                 Some(chksum) => krates.push(Krate {
                     name: p.name,
                     version: p.version,
-                    source: Source::CratesIo(chksum),
+                    source: Source::Registry {
+                        registry: Arc::clone(registry),
+                        chksum,
+                    },
                 }),
                 None => {
-                    write!(
-                        &mut lookup,
-                        "checksum {} {} (registry+https://github.com/rust-lang/crates.io-index)",
-                        p.name, p.version
-                    )
-                    .unwrap();
+                    write!(&mut lookup, "checksum {} {} ({})", p.name, p.version, source).unwrap();
 
                     if let Some(chksum) = locks.metadata.remove(&lookup) {
                         krates.push(Krate {
                             name: p.name,
                             version: p.version,
-                            source: Source::CratesIo(chksum),
+                            source: Source::Registry {
+                                registry: Arc::clone(registry),
+                                chksum,
+                            },
                         })
                     }
 
                     lookup.clear();
                 }
             }
+        } else if source.starts_with("registry+") {
+            error!(
+                "{}-{} is sourced from an unknown registry: {}",
+                p.name, p.version, source
+            );
         } else {
             // We support exactly one form of git sources, rev specififers
             // eg. git+https://github.com/EmbarkStudios/rust-build-helper?rev=9135717#91357179ba2ce6ec7e430a2323baab80a8f7d9b3
@@ -268,7 +463,36 @@ pub fn read_lock_file<P: AsRef<Path>>(lock_path: P) -> Result<Vec<Krate>, Error>
                 }
             };
 
-            match Source::from_git_url(&url) {
+            let canonicalized = match util::Canonicalized::try_from(&url) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!(
+                        "failed to canonicalize git url {} for {}-{}: {}",
+                        url, p.name, p.version, e
+                    );
+                    continue;
+                }
+            };
+            let ident = canonicalized.ident();
+
+            let repo = match git_repos.entry(ident.clone()) {
+                std::collections::btree_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::btree_map::Entry::Vacant(e) => {
+                    let dir = git_cache_dir.join(&ident);
+                    match git::clone_or_fetch(canonicalized.as_ref(), &dir) {
+                        Ok(repo) => e.insert(repo),
+                        Err(err) => {
+                            error!(
+                                "failed to fetch git source {} for {}-{}: {}",
+                                url, p.name, p.version, err
+                            );
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            match Source::from_git_url_verified(&url, repo) {
                 Ok(src) => {
                     krates.push(Krate {
                         name: p.name,
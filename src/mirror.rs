@@ -1,63 +1,174 @@
-use crate::{fetch, util, Ctx, Krate, Source};
-use anyhow::Error;
+use crate::{bundle, db, eligibility, fetch, git, util, Ctx, Krate, Registry, Source};
+use anyhow::{Context, Error};
+use futures::stream::{self, StreamExt};
 use log::{error, info};
-use std::{convert::TryFrom, time::Duration};
+use std::{collections::HashSet, convert::TryFrom, path::Path, sync::Arc, time::Duration};
+
+/// How many crates to fetch + upload concurrently in [`locked_crates`].
+/// Bounds the number of simultaneous network round-trips rather than firing
+/// one per krate at once, which could otherwise exhaust sockets/fds on large
+/// lockfiles.
+const UPLOAD_CONCURRENCY: usize = 16;
+
+/// Mirrors the index of every configured registry, skipping any whose last
+/// mirrored copy is still within `max_stale`.
+pub async fn registry_index(
+    ctx: &Ctx,
+    registries: &[Arc<Registry>],
+    max_stale: Duration,
+) -> Result<(), Error> {
+    for registry in registries {
+        if let Err(e) = mirror_index(ctx, registry, max_stale).await {
+            error!(
+                "failed to mirror index for registry '{}': {}",
+                registry.ident, e
+            );
+        }
+    }
+
+    Ok(())
+}
 
-pub fn registry_index(ctx: &Ctx<'_>, max_stale: Duration) -> Result<(), Error> {
-    let url = url::Url::parse("git+https://github.com/rust-lang/crates.io-index.git")?;
-    let canonicalized = util::Canonicalized::try_from(&url)?;
+async fn mirror_index(ctx: &Ctx, registry: &Arc<Registry>, max_stale: Duration) -> Result<(), Error> {
+    let canonicalized = util::Canonicalized::try_from(&registry.index)?;
     let ident = canonicalized.ident();
+    let index_name = format!("{}-index", registry.ident);
+
+    // Consult the catalog for the last time we mirrored this index, and only
+    // update it if it's stale. This is an indexed DB lookup rather than a
+    // network round-trip to the backend.
+    if let Some(last_updated) = db::last_uploaded(&ctx.db, &index_name).await? {
+        let now = chrono::Utc::now();
+        let max_dur = chrono::Duration::from_std(max_stale)?;
+
+        if now - last_updated < max_dur {
+            info!(
+                "{} was last updated {}, skipping update as it less than {:?} old",
+                index_name, last_updated, max_stale
+            );
+            return Ok(());
+        }
+    }
+
+    // `clone_or_fetch` and the packing below do blocking network I/O and
+    // CPU-bound work respectively; run them on the blocking pool so they
+    // don't stall whichever executor thread would otherwise pick up this
+    // async task for the whole duration.
+    let local_dir = ctx.root_dir.join("registry").join(&registry.ident);
+    let clone_url = canonicalized.as_ref().clone();
+    let repo = tokio::task::spawn_blocking(move || git::clone_or_fetch(&clone_url, &local_dir))
+        .await
+        .context("clone/fetch task panicked")??;
+
+    let blocking_repo = repo.clone();
+    let tip = tokio::task::spawn_blocking(move || git::resolve_rev(&blocking_repo, "HEAD"))
+        .await
+        .context("resolve_rev task panicked")??;
+
+    // Refresh the eligibility cache (yanked flags, last-seen times) while we
+    // already have the index checked out, so `prune` doesn't need to walk it
+    // again separately.
+    let now = chrono::Utc::now();
+    if let Err(e) = eligibility::refresh(&ctx.db, &registry.ident, &repo, &tip, now).await {
+        error!(
+            "failed to refresh eligibility cache for '{}': {}",
+            index_name, e
+        );
+    }
+
+    let have = db::last_index_oid(&ctx.db, &index_name).await?;
+
+    if have.as_deref() == Some(tip.as_str()) {
+        info!("{} is already at {}, nothing to mirror", index_name, tip);
+        return Ok(());
+    }
+
+    // Once we've recorded a prior oid, ship a thin bundle of just the new
+    // objects; otherwise this is the first mirror and we need the full
+    // object set as the base that future bundles get unbundled onto.
+    let blocking_repo = repo.clone();
+    let blocking_have = have.clone();
+    let blocking_tip = tip.clone();
+    let payload = tokio::task::spawn_blocking(move || match &blocking_have {
+        Some(have) => bundle::create(&blocking_repo, Some(have), &blocking_tip).map(|b| b.data),
+        None => git::pack_objects(&blocking_repo),
+    })
+    .await
+    .context("pack task panicked")??;
 
-    // Create a fake krate for the index, we don't have to worry about clashing
-    // since we use a `.` which is not an allowed character in crate names
     let krate = Krate {
-        name: "crates.io-index".to_owned(),
+        name: index_name.clone(),
         version: "1.0.0".to_owned(),
         source: Source::Git {
-            url: canonicalized.as_ref().clone(),
+            url: canonicalized.into(),
             ident,
-            rev: String::new(),
+            rev: tip.clone(),
         },
     };
 
-    // Retrieve the metadata for the last updated registry entry, and update
-    // only it if it's stale
-    if let Ok(last_updated) = ctx.backend.updated(&krate) {
-        if let Some(last_updated) = last_updated {
-            let now = chrono::Utc::now();
-            let max_dur = chrono::Duration::from_std(max_stale)?;
-
-            if now - last_updated < max_dur {
-                info!(
-                    "crates.io-index was last updated {}, skipping update as it less than {:?} old",
-                    last_updated, max_stale
-                );
-                return Ok(());
-            }
-        }
-    }
+    ctx.backend.upload(payload, &krate).await?;
+    db::record_upload(&ctx.db, &krate).await?;
+    db::record_index_oid(&ctx.db, &index_name, &tip).await
+}
 
-    let index = fetch::registry(canonicalized.as_ref())?;
+/// Reconstructs a full copy of `registry`'s index at `dest` by downloading
+/// the base artifact followed by every incremental bundle recorded on top of
+/// it, in the order they were mirrored, and applying them in turn.
+pub async fn unbundle_index(ctx: &Ctx, registry: &Arc<Registry>, dest: &Path) -> Result<(), Error> {
+    let index_name = format!("{}-index", registry.ident);
+    let revisions = db::index_revisions(&ctx.db, &index_name).await?;
 
-    ctx.backend.upload(index, &krate)
-}
+    // Address artifacts by the same canonicalized ident/url `mirror_index`
+    // uploaded them under, so the cloud ids match
+    let canonicalized = util::Canonicalized::try_from(&registry.index)?;
+    let ident = canonicalized.ident();
+    let url = canonicalized.as_ref().clone();
 
-pub fn locked_crates(ctx: &Ctx<'_>) -> Result<(), Error> {
-    info!("mirroring {} crates", ctx.krates.len());
+    let (base_rev, bundle_revs) = match revisions.split_first() {
+        Some((base, rest)) => (base, rest),
+        None => anyhow::bail!("no mirrored revisions found for '{}'", index_name),
+    };
 
-    info!("checking existing stored crates...");
-    let mut names = ctx.backend.list()?;
+    let base_krate = Krate {
+        name: index_name.clone(),
+        version: "1.0.0".to_owned(),
+        source: Source::Git {
+            url: url.clone(),
+            ident: ident.clone(),
+            rev: base_rev.clone(),
+        },
+    };
 
-    names.sort();
+    let base_pack = ctx.backend.fetch(&base_krate).await?;
+    std::fs::create_dir_all(dest)?;
+    git::unpack_base(dest, &base_pack)?;
+
+    let mut bundles = Vec::with_capacity(bundle_revs.len());
+    for rev in bundle_revs {
+        let krate = Krate {
+            name: index_name.clone(),
+            version: "1.0.0".to_owned(),
+            source: Source::Git {
+                url: url.clone(),
+                ident: ident.clone(),
+                rev: rev.clone(),
+            },
+        };
+
+        bundles.push(ctx.backend.fetch(&krate).await?);
+    }
 
-    let mut to_mirror = Vec::with_capacity(names.len());
-    for krate in ctx.krates {
-        let cid = format!("{}", krate.cloud_id());
-        if names
-            .binary_search_by(|name| name.as_str().cmp(&cid))
-            .is_err()
-        {
-            to_mirror.push(krate);
+    bundle::unbundle(dest, &bundles)
+}
+
+pub async fn locked_crates(ctx: &Ctx) -> Result<(), Error> {
+    info!("mirroring {} crates", ctx.krates.len());
+
+    info!("checking catalog for already uploaded crates...");
+    let mut to_mirror = Vec::with_capacity(ctx.krates.len());
+    for krate in &ctx.krates {
+        if !db::is_uploaded(&ctx.db, krate).await? {
+            to_mirror.push(krate.clone());
         }
     }
 
@@ -72,18 +183,63 @@ pub fn locked_crates(ctx: &Ctx<'_>) -> Result<(), Error> {
 
     info!("uploading {} crates...", to_mirror.len());
 
-    use rayon::prelude::*;
-
-    to_mirror
-        .par_iter()
-        .for_each(|krate| match fetch::from_crates_io(&ctx.client, krate) {
-            Err(e) => error!("failed to retrieve {}: {}", krate, e),
-            Ok(buffer) => {
-                if let Err(e) = ctx.backend.upload(buffer, krate) {
-                    error!("failed to upload {} to GCS: {}", krate, e);
-                }
+    stream::iter(&to_mirror)
+        .for_each_concurrent(UPLOAD_CONCURRENCY, |krate| async move {
+            match fetch::from_registry(&ctx.client, krate).await {
+                Err(e) => error!("failed to retrieve {}: {}", krate, e),
+                Ok(buffer) => match ctx.backend.upload(buffer, krate).await {
+                    Err(e) => error!("failed to upload {} to backend: {}", krate, e),
+                    Ok(_) => {
+                        if let Err(e) = db::record_upload(&ctx.db, krate).await {
+                            error!("failed to record upload of {} in catalog: {}", krate, e);
+                        }
+                    }
+                },
             }
-        });
+        })
+        .await;
 
     Ok(())
 }
+
+/// Deletes backend objects that are no longer referenced by any krate we're
+/// currently mirroring and are either yanked or haven't been seen in the
+/// registry index within `retention`, reclaiming storage on long-lived
+/// mirrors. Returns the cloud ids of everything that was pruned.
+pub async fn prune(ctx: &Ctx, retention: chrono::Duration) -> Result<Vec<String>, Error> {
+    let tracked: HashSet<String> = ctx
+        .krates
+        .iter()
+        .map(|krate| krate.cloud_id().to_string())
+        .collect();
+
+    let stored = ctx.backend.list().await?;
+    let mut pruned = Vec::new();
+
+    for cloud_id in stored {
+        if tracked.contains(&cloud_id) {
+            continue;
+        }
+
+        if !db::is_prune_eligible(&ctx.db, &cloud_id, retention).await? {
+            continue;
+        }
+
+        match ctx.backend.delete(&cloud_id).await {
+            Ok(()) => pruned.push(cloud_id),
+            Err(e) => error!("failed to prune {}: {}", cloud_id, e),
+        }
+    }
+
+    info!("pruned {} stale/yanked artifacts", pruned.len());
+
+    Ok(pruned)
+}
+
+/// Forces the next `registry_index` walk to re-evaluate every crate's
+/// yanked status and last-seen time from scratch, rather than trusting the
+/// existing eligibility cache. Useful after a retention policy change, or if
+/// the cache is suspected to have drifted from the real index.
+pub async fn bust_cache(ctx: &Ctx) -> Result<(), Error> {
+    db::bust_eligibility_cache(&ctx.db).await
+}